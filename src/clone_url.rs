@@ -0,0 +1,90 @@
+//! Picks which URL (SSH vs HTTPS) to clone a repo with, and canonicalizes it so the same repo
+//! always maps to the same identity regardless of whether it's expressed as scp-style
+//! (`git@host:owner/name.git`) or a full URL, and with or without a trailing `.git`.
+
+use git_url_parse::GitUrl;
+
+use forge::RemoteRepo;
+
+/// The `clone_protocol` config option: which of a repo's two URLs to prefer.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneProtocol {
+    Ssh,
+    Https,
+    Auto,
+}
+
+impl Default for CloneProtocol {
+    fn default() -> CloneProtocol {
+        CloneProtocol::Auto
+    }
+}
+
+/// Picks `ssh_url` or `clone_url` per `protocol`, falling back to the other when the preferred
+/// one is blank. `Auto` prefers SSH, matching ghopac's historical default.
+pub fn select_clone_url(protocol: CloneProtocol, repo: &RemoteRepo) -> Option<String> {
+    let (preferred, fallback) = match protocol {
+        CloneProtocol::Https => (&repo.clone_url, &repo.ssh_url),
+        CloneProtocol::Ssh | CloneProtocol::Auto => (&repo.ssh_url, &repo.clone_url),
+    };
+    if !preferred.trim().is_empty() {
+        Some(preferred.clone())
+    } else if !fallback.trim().is_empty() {
+        Some(fallback.clone())
+    } else {
+        None
+    }
+}
+
+/// A `host/owner/name` triple that identifies a repo independent of clone URL form, used so the
+/// same repo queued via two different URL styles is still recognized as a dup.
+pub fn canonical_identity(url: &str) -> Option<String> {
+    GitUrl::parse(url).ok().map(|parsed| format!("{}/{}/{}", parsed.host.unwrap_or_default(), parsed.owner.unwrap_or_default(), parsed.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(ssh_url: &str, clone_url: &str) -> RemoteRepo {
+        RemoteRepo {
+            name:      "some-repo".to_owned(),
+            ssh_url:   ssh_url.to_owned(),
+            clone_url: clone_url.to_owned(),
+            archived:  false,
+            fork:      false,
+        }
+    }
+
+    #[test]
+    fn select_clone_url_prefers_ssh_for_auto_and_ssh() {
+        let r = repo("git@example.com:owner/some-repo.git", "https://example.com/owner/some-repo.git");
+        assert_eq!(select_clone_url(CloneProtocol::Auto, &r), Some(r.ssh_url.clone()));
+        assert_eq!(select_clone_url(CloneProtocol::Ssh, &r), Some(r.ssh_url.clone()));
+        assert_eq!(select_clone_url(CloneProtocol::Https, &r), Some(r.clone_url.clone()));
+    }
+
+    #[test]
+    fn select_clone_url_falls_back_when_preferred_is_blank() {
+        let r = repo("", "https://example.com/owner/some-repo.git");
+        assert_eq!(select_clone_url(CloneProtocol::Ssh, &r), Some(r.clone_url.clone()));
+
+        let r = repo("git@example.com:owner/some-repo.git", "");
+        assert_eq!(select_clone_url(CloneProtocol::Https, &r), Some(r.ssh_url.clone()));
+    }
+
+    #[test]
+    fn select_clone_url_is_none_when_both_are_blank() {
+        let r = repo("", "");
+        assert_eq!(select_clone_url(CloneProtocol::Auto, &r), None);
+    }
+
+    #[test]
+    fn canonical_identity_matches_scp_and_url_style_for_the_same_repo() {
+        let scp_style = canonical_identity("git@example.com:owner/some-repo.git");
+        let url_style = canonical_identity("https://example.com/owner/some-repo.git");
+        assert!(scp_style.is_some());
+        assert_eq!(scp_style, url_style);
+    }
+}
@@ -0,0 +1,258 @@
+//! Abstraction over the different Git forges (GitHub, Gitea, GitLab, ...) that expose an
+//! "enumerate all repos in an org/group" endpoint. `main()` only ever talks to a
+//! `Box<ForgeClient>`, chosen at startup based on `ConfigOrg::forge`.
+
+use std::error;
+use std::str;
+
+use hyper::Client;
+use hyper::header::{Authorization, Headers};
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+
+use hubcaps::{Credentials, Github};
+
+/// The subset of a forge's repo metadata that ghopac needs in order to queue a sync.
+#[derive(Debug, Clone)]
+pub struct RemoteRepo {
+    pub name:       String,
+    pub ssh_url:    String,
+    pub clone_url:  String,
+    pub archived:   bool,
+    pub fork:       bool,
+}
+
+/// Implemented once per forge kind. `list_repos` returns every repo visible to the configured
+/// credentials for the given org/group, unfiltered - callers apply `include`/`exclude`/
+/// `skip_archived`/`skip_forks` themselves.
+pub trait ForgeClient {
+    fn list_repos(&self, org: &str) -> Result<Vec<RemoteRepo>, Box<error::Error>>;
+}
+
+/// Wraps the existing `hubcaps::Github` client so it can be used behind `ForgeClient`.
+pub struct GithubForge {
+    github: Github,
+}
+
+impl GithubForge {
+    pub fn new(github: Github) -> GithubForge {
+        GithubForge { github: github }
+    }
+}
+
+impl ForgeClient for GithubForge {
+    fn list_repos(&self, org: &str) -> Result<Vec<RemoteRepo>, Box<error::Error>> {
+        let list_options = Default::default();
+        let org_repos = self.github.org(org.to_owned()).repos().iter(&list_options)?;
+        Ok(org_repos
+            .map(|r| {
+                RemoteRepo {
+                    name:      r.name,
+                    ssh_url:   r.ssh_url,
+                    clone_url: r.clone_url,
+                    archived:  r.archived,
+                    fork:      r.fork,
+                }
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct GiteaRepo {
+    name:       String,
+    ssh_url:    String,
+    clone_url:  String,
+    archived:   bool,
+    fork:       bool,
+}
+
+/// Talks to a self-hosted Gitea instance's `/api/v1/orgs/{org}/repos` endpoint.
+pub struct GiteaForge {
+    base_url: String,
+    token:    Option<String>,
+}
+
+impl GiteaForge {
+    pub fn new(base_url: String, token: Option<String>) -> GiteaForge {
+        GiteaForge { base_url: base_url, token: token }
+    }
+}
+
+/// Requested page size for Gitea's `limit` query param - chosen just to keep the number of
+/// round-trips down for orgs with hundreds of repos, not a Gitea-imposed maximum.
+const GITEA_PAGE_SIZE: u32 = 50;
+
+impl ForgeClient for GiteaForge {
+    fn list_repos(&self, org: &str) -> Result<Vec<RemoteRepo>, Box<error::Error>> {
+        let mut all_repos = Vec::new();
+        let mut page = 1;
+        loop {
+            let url = format!("{}/api/v1/orgs/{}/repos?page={}&limit={}", self.base_url.trim_right_matches('/'), org, page, GITEA_PAGE_SIZE);
+            let repos: Vec<GiteaRepo> = get_json(&url, self.token.as_ref())?;
+            let got = repos.len();
+            all_repos.extend(repos.into_iter().map(|r| {
+                RemoteRepo {
+                    name:      r.name,
+                    ssh_url:   r.ssh_url,
+                    clone_url: r.clone_url,
+                    archived:  r.archived,
+                    fork:      r.fork,
+                }
+            }));
+            if got < GITEA_PAGE_SIZE as usize {
+                break;
+            }
+            page += 1;
+        }
+        Ok(all_repos)
+    }
+}
+
+#[derive(Deserialize)]
+struct GitlabProject {
+    name:                String,
+    ssh_url_to_repo:     String,
+    http_url_to_repo:    String,
+    archived:            bool,
+    #[serde(default)]
+    forked_from_project: Option<serde_json::Value>,
+}
+
+/// Talks to a self-hosted GitLab instance's `/api/v4/groups/{group}/projects` endpoint.
+pub struct GitlabForge {
+    base_url: String,
+    token:    Option<String>,
+}
+
+impl GitlabForge {
+    pub fn new(base_url: String, token: Option<String>) -> GitlabForge {
+        GitlabForge { base_url: base_url, token: token }
+    }
+}
+
+const GITLAB_PAGE_SIZE: u32 = 100;
+
+impl ForgeClient for GitlabForge {
+    fn list_repos(&self, org: &str) -> Result<Vec<RemoteRepo>, Box<error::Error>> {
+        let mut all_projects = Vec::new();
+        let mut page = 1;
+        loop {
+            let url = format!("{}/api/v4/groups/{}/projects?per_page={}&page={}", self.base_url.trim_right_matches('/'), org, GITLAB_PAGE_SIZE, page);
+            let (projects, headers) = get_json_with_headers::<Vec<GitlabProject>>(&url, self.token.as_ref())?;
+            all_projects.extend(projects.into_iter().map(|p| {
+                RemoteRepo {
+                    name:      p.name,
+                    ssh_url:   p.ssh_url_to_repo,
+                    clone_url: p.http_url_to_repo,
+                    archived:  p.archived,
+                    fork:      p.forked_from_project.is_some(),
+                }
+            }));
+            match gitlab_next_page(&headers) {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+        Ok(all_projects)
+    }
+}
+
+/// GitLab advertises the next page via an `X-Next-Page` response header, empty when the current
+/// page is the last one - simpler to rely on than parsing the RFC 5988 `Link` header.
+fn gitlab_next_page(headers: &Headers) -> Option<u32> {
+    headers
+        .get_raw("x-next-page")
+        .and_then(|lines| lines.get(0))
+        .and_then(|bytes| str::from_utf8(bytes).ok())
+        .and_then(|s| if s.is_empty() { None } else { s.parse().ok() })
+}
+
+#[derive(Deserialize)]
+struct BitbucketCloneLink {
+    name: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketLinks {
+    clone: Vec<BitbucketCloneLink>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketRepo {
+    name:   String,
+    links:  BitbucketLinks,
+    #[serde(default)]
+    parent: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketReposResponse {
+    values: Vec<BitbucketRepo>,
+    /// Present (a full URL) whenever there's another page to follow; absent on the last page.
+    #[serde(default)]
+    next: Option<String>,
+}
+
+const BITBUCKET_DEFAULT_BASE_URL: &'static str = "https://api.bitbucket.org/2.0";
+
+/// Talks to Bitbucket Cloud's `/2.0/repositories/{workspace}` endpoint.
+pub struct BitbucketForge {
+    base_url: String,
+    token:    Option<String>,
+}
+
+impl BitbucketForge {
+    pub fn new(base_url: Option<String>, token: Option<String>) -> BitbucketForge {
+        BitbucketForge { base_url: base_url.unwrap_or_else(|| BITBUCKET_DEFAULT_BASE_URL.to_owned()), token: token }
+    }
+}
+
+impl ForgeClient for BitbucketForge {
+    fn list_repos(&self, org: &str) -> Result<Vec<RemoteRepo>, Box<error::Error>> {
+        let mut all_repos = Vec::new();
+        let mut url = format!("{}/repositories/{}", self.base_url.trim_right_matches('/'), org);
+        loop {
+            let response: BitbucketReposResponse = get_json(&url, self.token.as_ref())?;
+            all_repos.extend(response.values.into_iter().map(|r| {
+                let ssh_url = r.links.clone.iter().find(|link| link.name == "ssh").map(|link| link.href.clone()).unwrap_or_default();
+                let clone_url = r.links.clone.iter().find(|link| link.name == "https").map(|link| link.href.clone()).unwrap_or_default();
+                RemoteRepo {
+                    name:      r.name,
+                    ssh_url:   ssh_url,
+                    clone_url: clone_url,
+                    archived:  false,
+                    fork:      r.parent.is_some(),
+                }
+            }));
+            match response.next {
+                Some(next_url) => url = next_url,
+                None => break,
+            }
+        }
+        Ok(all_repos)
+    }
+}
+
+fn get_json<T>(url: &str, token: Option<&String>) -> Result<T, Box<error::Error>>
+    where T: ::serde::de::DeserializeOwned
+{
+    Ok(get_json_with_headers(url, token)?.0)
+}
+
+/// Like `get_json`, but also hands back the response headers so callers can follow
+/// forge-specific pagination (GitLab's `X-Next-Page`, ...) without a second round-trip.
+fn get_json_with_headers<T>(url: &str, token: Option<&String>) -> Result<(T, Headers), Box<error::Error>>
+    where T: ::serde::de::DeserializeOwned
+{
+    let client = Client::with_connector(HttpsConnector::new(NativeTlsClient::new()?));
+    let mut request = client.get(url);
+    if let Some(token) = token {
+        request = request.header(Authorization(format!("Bearer {}", token)));
+    }
+    let response = request.send()?;
+    let headers = response.headers.clone();
+    let body = serde_json::from_reader(response)?;
+    Ok((body, headers))
+}
@@ -0,0 +1,235 @@
+//! In-process cloning/fetching, replacing the `git` subprocess. Modeled loosely on cargo's
+//! `GitRemote`/`GitDatabase`/`GitCheckout` split: a `GitRemote` is just a clone URL; syncing it
+//! either creates a `GitDatabase` (first clone) or brings an existing one up to date with a
+//! fetch, and the `GitDatabase` then makes the working tree match the resolved reference (there
+//! is no separate checkout type).
+
+use std::error;
+use std::fmt;
+use std::path;
+use std::thread;
+use std::time::Duration;
+
+use git2;
+
+use slog;
+
+/// A remote repository, identified only by its clone URL until it's synced to a local path.
+/// Carries the credentials the fetch/clone should try: an HTTPS token (from
+/// `Config::resolve_github_token`/a forge's own token) and/or an SSH private key path, tried
+/// alongside ssh-agent.
+pub struct GitRemote {
+    url:          String,
+    https_token:  Option<String>,
+    ssh_key_path: Option<path::PathBuf>,
+    verbose:      bool,
+}
+
+/// Which revision a repo should end up on, modeled on cargo's `GitReference`. `None` (the
+/// absence of this enum, in config) means "whatever branch is checked out" - the default branch
+/// for a fresh clone.
+///
+/// Uses serde's default externally-tagged representation (e.g. `{"branch": "main"}`) - an
+/// internally-tagged repr (`#[serde(tag = "type")]`) can't serialize newtype variants wrapping a
+/// plain string with serde_json.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum GitReference {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+/// A local clone of a `GitRemote`, already on disk at `path`.
+pub struct GitDatabase {
+    repo: git2::Repository,
+}
+
+#[derive(Debug)]
+pub struct GitSyncError {
+    message: String,
+}
+
+impl fmt::Display for GitSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for GitSyncError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<git2::Error> for GitSyncError {
+    fn from(e: git2::Error) -> GitSyncError {
+        GitSyncError { message: e.message().to_owned() }
+    }
+}
+
+const FETCH_REFSPEC: &'static str = "+refs/heads/*:refs/remotes/origin/*";
+const RETRY_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+impl GitRemote {
+    pub fn new(url: &str, https_token: Option<String>, ssh_key_path: Option<path::PathBuf>, verbose: bool) -> GitRemote {
+        GitRemote { url: url.to_owned(), https_token: https_token, ssh_key_path: ssh_key_path, verbose: verbose }
+    }
+
+    /// Clones this remote to `path`, creating the `GitDatabase` for it.
+    pub fn checkout(&self, logger: &slog::Logger, path: &path::Path) -> Result<GitDatabase, GitSyncError> {
+        let url = self.url.clone();
+        let repo = with_retry(logger, || {
+            let mut callbacks = git2::RemoteCallbacks::new();
+            install_credentials_callback(&mut callbacks, &self.https_token, &self.ssh_key_path);
+            install_progress_callback(&mut callbacks, logger, self.verbose);
+            let mut fetch_options = git2::FetchOptions::new();
+            fetch_options.remote_callbacks(callbacks);
+            let mut builder = git2::build::RepoBuilder::new();
+            builder.fetch_options(fetch_options);
+            builder.clone(&url, path)
+        })?;
+        Ok(GitDatabase { repo: repo })
+    }
+
+    /// Opens the already-cloned repo at `path` and fetches the latest refs from origin.
+    pub fn fetch(&self, logger: &slog::Logger, path: &path::Path) -> Result<GitDatabase, GitSyncError> {
+        let repo = git2::Repository::open(path)?;
+        {
+            let mut remote = repo.find_remote("origin").or_else(|_| repo.remote_anonymous(&self.url))?;
+            with_retry(logger, || {
+                let mut callbacks = git2::RemoteCallbacks::new();
+                install_credentials_callback(&mut callbacks, &self.https_token, &self.ssh_key_path);
+                install_progress_callback(&mut callbacks, logger, self.verbose);
+                let mut fetch_options = git2::FetchOptions::new();
+                fetch_options.remote_callbacks(callbacks);
+                fetch_options.prune(git2::FetchPrune::On);
+                remote.fetch(&[FETCH_REFSPEC], Some(&mut fetch_options), None)
+            })?;
+        }
+        Ok(GitDatabase { repo: repo })
+    }
+}
+
+impl GitDatabase {
+    /// Fast-forwards the working tree's current branch to `origin/<branch>`. Used for the
+    /// default (no explicit reference) case, equivalent to `git pull --prune`.
+    pub fn fast_forward_to_head(&self, logger: &slog::Logger) -> Result<(), GitSyncError> {
+        let head = self.repo.head()?;
+        let branch = (if head.is_branch() { head.shorthand() } else { None }).ok_or_else(|| GitSyncError { message: "detached HEAD, nothing to fast-forward".to_owned() })?.to_owned();
+        self.fast_forward_to_branch(logger, &branch)
+    }
+
+    /// Fast-forwards `branch` to `origin/<branch>`, refusing (like `git pull`) rather than
+    /// clobbering anything when the working tree has local changes (tracked or untracked) or the
+    /// move isn't a genuine fast-forward of the local branch's current tip.
+    pub fn fast_forward_to_branch(&self, logger: &slog::Logger, branch: &str) -> Result<(), GitSyncError> {
+        let remote_ref = format!("refs/remotes/origin/{}", branch);
+        let target = self.repo.find_reference(&remote_ref)?.peel_to_commit()?;
+
+        if self.has_local_changes()? {
+            return Err(GitSyncError { message: format!("working tree has local changes, refusing to fast-forward {}", branch) });
+        }
+
+        if let Ok(local_branch) = self.repo.find_branch(branch, git2::BranchType::Local) {
+            let local_tip = local_branch.get().peel_to_commit()?;
+            if local_tip.id() != target.id() && !self.repo.graph_descendant_of(target.id(), local_tip.id())? {
+                return Err(GitSyncError { message: format!("{} is not a fast-forward of local branch {}, refusing to move it", remote_ref, branch) });
+            }
+        }
+
+        debug!(logger, "Fast-forwarding {} to {}", branch, target.id());
+        self.repo.checkout_tree(target.as_object(), Some(git2::build::CheckoutBuilder::new().force()))?;
+        self.repo.set_head_detached(target.id())?;
+        self.repo.set_head(&format!("refs/heads/{}", branch))?;
+        let mut branch_ref = self.repo
+            .find_branch(branch, git2::BranchType::Local)
+            .or_else(|_| self.repo.branch(branch, &target, true))?;
+        branch_ref.get_mut().set_target(target.id(), "ghopac: fast-forward")?;
+        Ok(())
+    }
+
+    /// Whether the working tree has any uncommitted changes - to tracked files, or untracked
+    /// files whose presence alone would make a forced checkout of the target tree destructive.
+    fn has_local_changes(&self) -> Result<bool, GitSyncError> {
+        let mut status_options = git2::StatusOptions::new();
+        status_options.include_untracked(true);
+        let statuses = self.repo.statuses(Some(&mut status_options))?;
+        Ok(!statuses.is_empty())
+    }
+
+    /// Detaches HEAD at the tag or revision's resolved commit, refusing (like `fast_forward_to_branch`)
+    /// rather than clobbering anything when the working tree is dirty.
+    pub fn checkout_detached(&self, logger: &slog::Logger, spec: &str) -> Result<(), GitSyncError> {
+        let object = self.repo.revparse_single(spec)?;
+        let commit = object.peel_to_commit()?;
+
+        if self.has_local_changes()? {
+            return Err(GitSyncError { message: format!("working tree has local changes, refusing to check out {}", spec) });
+        }
+
+        debug!(logger, "Checking out detached {} ({})", spec, commit.id());
+        self.repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::new().force()))?;
+        self.repo.set_head_detached(commit.id())?;
+        Ok(())
+    }
+}
+
+/// Tries, in order: ssh-agent, then the configured SSH key path, then username/token for HTTPS
+/// remotes using the token the caller resolved (a PAT, a GitHub App installation token, or a
+/// forge's own configured token).
+fn install_credentials_callback<'a>(callbacks: &mut git2::RemoteCallbacks<'a>, https_token: &'a Option<String>, ssh_key_path: &'a Option<path::PathBuf>) {
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+            let username = username_from_url.unwrap_or("git");
+            if let Ok(cred) = git2::Cred::ssh_key_from_agent(username) {
+                return Ok(cred);
+            }
+            if let Some(ref key_path) = *ssh_key_path {
+                if let Ok(cred) = git2::Cred::ssh_key(username, None, key_path, None) {
+                    return Ok(cred);
+                }
+            }
+        }
+        if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(ref token) = *https_token {
+                return git2::Cred::userpass_plaintext(username_from_url.unwrap_or("x-access-token"), token);
+            }
+        }
+        Err(git2::Error::from_str(&format!("No credentials available for {}", url)))
+    });
+}
+
+/// Only logs transfer progress when `verbose` is set - `transfer_progress` can fire on every
+/// object received, which floods stdout on a large clone if it's unconditional.
+fn install_progress_callback<'a>(callbacks: &mut git2::RemoteCallbacks<'a>, logger: &'a slog::Logger, verbose: bool) {
+    callbacks.transfer_progress(move |progress| {
+        if verbose {
+            debug!(logger, "Received {}/{} objects, {} bytes", progress.received_objects(), progress.total_objects(), progress.received_bytes());
+        }
+        true
+    });
+}
+
+/// Calls `f` up to `RETRY_ATTEMPTS` times in all, with a fixed `RETRY_BACKOFF` delay between attempts,
+/// but only when the failure is a transient network error (`ErrorClass::Net`) - anything else
+/// (auth failure, bad ref, ...) is returned immediately.
+fn with_retry<T, F>(logger: &slog::Logger, mut f: F) -> Result<T, git2::Error>
+    where F: FnMut() -> Result<T, git2::Error>
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                if attempt >= RETRY_ATTEMPTS || e.class() != git2::ErrorClass::Net {
+                    return Err(e);
+                }
+                warn!(logger, "Transient network error (attempt {}/{}), retrying in {:?}: {}", attempt, RETRY_ATTEMPTS, RETRY_BACKOFF, e.message());
+                thread::sleep(RETRY_BACKOFF);
+            }
+        }
+    }
+}
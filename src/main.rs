@@ -45,9 +45,20 @@ extern crate hyper;
 extern crate hyper_native_tls;
 extern crate serde_json;
 
+extern crate base64;
+extern crate git2;
+extern crate git_url_parse;
+extern crate jsonwebtoken;
+extern crate regex;
 extern crate spmc;
 extern crate xdg;
 
+mod clone_url;
+mod forge;
+mod git;
+mod github_app;
+mod secret;
+
 use std::boxed::Box;
 use std::cmp;
 use std::error;
@@ -55,6 +66,7 @@ use std::fs;
 use std::mem;
 use std::path;
 use std::process;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::thread;
 
@@ -63,26 +75,177 @@ use hyper::Client;
 use hyper_native_tls::NativeTlsClient;
 use hyper::net::HttpsConnector;
 
+use regex::Regex;
+
 use slog::Drain;
 
+use clone_url::CloneProtocol;
+use forge::{ForgeClient, GithubForge, GiteaForge, GitlabForge, BitbucketForge};
+use git::GitReference;
+use github_app::GithubAppConfig;
+use secret::SecretSource;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Forge {
+    Github,
+    Gitea,
+    Gitlab,
+    Bitbucket,
+}
+
+impl Default for Forge {
+    fn default() -> Forge {
+        Forge::Github
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct ConfigOrg {
     org: String,
     path: String,
+    #[serde(default)]
+    forge: Forge,
+    base_url: Option<String>,
+    /// Credential for this org's own forge API (and for cloning its repos over HTTPS). Ignored
+    /// for `forge: github`, which authenticates with `Config::github_access_token`/`github_app`
+    /// instead.
+    forge_token: Option<SecretSource>,
+    reference: Option<GitReference>,
+    #[serde(default)]
+    clone_protocol: CloneProtocol,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    skip_archived: bool,
+    #[serde(default)]
+    skip_forks: bool,
+}
+
+impl ConfigOrg {
+    /// Resolves `forge_token` (if set) into a plain string for use as a Gitea/GitLab/Bitbucket
+    /// API bearer token and HTTPS clone credential.
+    fn resolve_forge_token(&self, logger: &slog::Logger) -> Option<String> {
+        match self.forge_token {
+            Some(ref source) => {
+                match source.resolve() {
+                    Ok(secret) => Some(secret.reveal().to_owned()),
+                    Err(e) => {
+                        error!(logger, "Unable to resolve forge_token for org `{}`: {}", self.org, e);
+                        None
+                    }
+                }
+            },
+            None => None,
+        }
+    }
+
+    /// Whether `repo` survives this org's `include`/`exclude`/`skip_archived`/`skip_forks`
+    /// filters. An empty `include` list means "everything passes" rather than "nothing passes".
+    fn repo_allowed(&self, logger: &slog::Logger, repo: &forge::RemoteRepo) -> bool {
+        if self.skip_archived && repo.archived {
+            return false;
+        }
+        if self.skip_forks && repo.fork {
+            return false;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|pattern| name_matches(logger, pattern, &repo.name)) {
+            return false;
+        }
+        if self.exclude.iter().any(|pattern| name_matches(logger, pattern, &repo.name)) {
+            return false;
+        }
+        true
+    }
+}
+
+fn name_matches(logger: &slog::Logger, pattern: &str, name: &str) -> bool {
+    match Regex::new(pattern) {
+        Ok(re) => re.is_match(name),
+        Err(e) => {
+            warn!(logger, "Invalid filter regex `{}`, ignoring it: {}", pattern, e);
+            false
+        }
+    }
+}
+
+/// A `syncpoints` entry: either a bare path (pull whatever's there, default behavior) or a path
+/// paired with a `reference` override for repos that need to track something other than the
+/// branch they already happen to be on.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Syncpoint {
+    Path(String),
+    WithReference { path: String, reference: GitReference },
+}
+
+impl Syncpoint {
+    fn path(&self) -> &str {
+        match *self {
+            Syncpoint::Path(ref path) => path,
+            Syncpoint::WithReference { ref path, .. } => path,
+        }
+    }
+
+    fn reference(&self) -> Option<GitReference> {
+        match *self {
+            Syncpoint::Path(_) => None,
+            Syncpoint::WithReference { ref reference, .. } => Some(reference.clone()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct Config {
-    github_access_token: Option<String>,
+    github_access_token: Option<SecretSource>,
+    github_app:          Option<GithubAppConfig>,
+    ssh_key_path:        Option<path::PathBuf>,
     orgs:                Option<Vec<ConfigOrg>>,
-    syncpoints:          Option<Vec<String>>,
+    syncpoints:          Option<Vec<Syncpoint>>,
     concurrency:         Option<u8>,
     verbose:             Option<bool>,
 }
 
+impl Config {
+    /// Resolves the token to authenticate to github.com with: a minted installation token when
+    /// `github_app` is configured (preferred, since it's short-lived), otherwise the resolved
+    /// `github_access_token` secret (a literal, an env var, or a command's stdout).
+    fn resolve_github_token(&self, logger: &slog::Logger) -> Option<String> {
+        match self.github_app {
+            Some(ref app_config) => {
+                match github_app::installation_token(app_config) {
+                    Ok(token) => Some(token),
+                    Err(e) => {
+                        error!(logger, "Unable to mint a GitHub App installation token: {}", e);
+                        None
+                    }
+                }
+            },
+            None => {
+                match self.github_access_token {
+                    Some(ref source) => {
+                        match source.resolve() {
+                            Ok(secret) => Some(secret.reveal().to_owned()),
+                            Err(e) => {
+                                error!(logger, "Unable to resolve github_access_token: {}", e);
+                                None
+                            }
+                        }
+                    },
+                    None => None,
+                }
+            },
+        }
+    }
+}
+
 struct GitRepoSyncRequest {
     path: path::PathBuf,
     clone_url: Option<String>,
+    clone_token: Option<String>,
+    reference: Option<GitReference>,
 }
 
 const PROGRAM_NAME: &'static str = "ghopac";
@@ -91,18 +254,41 @@ const DEFAULT_CONCURRENCY: u8 = 4;
 
 fn show_config_sample_and_exit_1() -> path::PathBuf {
     let sample_config = Config {
-        github_access_token: Some("Use a token from https://github.com/settings/tokens".to_owned()),
+        github_access_token: Some(SecretSource::Env { env: "GH_TOKEN".to_owned() }),
+        github_app: None,
+        ssh_key_path: Some(path::PathBuf::from("/home/me/.ssh/id_ed25519")),
         orgs: Some(vec![
             ConfigOrg {
                 org: "my_org".to_owned(),
                 path: "/my_org/source/directory".to_owned(),
+                forge: Forge::Github,
+                base_url: None,
+                forge_token: None,
+                reference: None,
+                clone_protocol: CloneProtocol::Auto,
+                include: vec![],
+                exclude: vec!["^archive-.*".to_owned()],
+                skip_archived: true,
+                skip_forks: true,
             },
             ConfigOrg {
-                org: "some_other_org".to_owned(),
-                path: "/some_other_org/source/directory".to_owned(),
+                org: "some_other_group".to_owned(),
+                path: "/some_other_group/source/directory".to_owned(),
+                forge: Forge::Gitlab,
+                base_url: Some("https://gitlab.example.com".to_owned()),
+                forge_token: Some(SecretSource::Command { command: vec!["pass".to_owned(), "show".to_owned(), "gitlab/token".to_owned()] }),
+                reference: Some(GitReference::Branch("release".to_owned())),
+                clone_protocol: CloneProtocol::Https,
+                include: vec![],
+                exclude: vec![],
+                skip_archived: false,
+                skip_forks: false,
             },
         ]),
-        syncpoints: Some(vec!["/some/previously/cloned/directory".to_owned(), "/some/other/previously/cloned/directory".to_owned()]),
+        syncpoints: Some(vec![
+            Syncpoint::Path("/some/previously/cloned/directory".to_owned()),
+            Syncpoint::WithReference { path: "/some/other/previously/cloned/directory".to_owned(), reference: GitReference::Tag("v1.2.3".to_owned()) },
+        ]),
         concurrency: Some(DEFAULT_CONCURRENCY),
         verbose: Some(true),
     };
@@ -119,80 +305,60 @@ fn configuration(logger: slog::Logger) -> Result<Config, Box<error::Error>> {
     Ok(serde_json::from_reader(config_file)?)
 }
 
-fn closest_ancestor_dir(path: Option<&path::Path>) -> Option<&path::Path> {
-    match path {
-        Some(path) => {
-            if path.exists() && path.is_dir() {
-                Some(path)
-            } else {
-                closest_ancestor_dir(path.parent())
-            }
-        },
-        None => None,
-    }
-}
-
 fn worker_thread(logger: slog::Logger, config: Arc<Config>, receiver: spmc::Receiver<GitRepoSyncRequest>) -> u16 {
     let mut error_count = 0;
     loop {
         match receiver.recv() {
             Ok(request) => {
-                let mut git_args = Vec::with_capacity(3);
-                if request.path.exists() {
-                    if request.path.is_dir() {
-                        git_args.append(&mut vec!["pull", "--prune"]);
-                    } else {
-                        error!(logger, "{} exists but is not a directory, skipping", request.path.to_str().unwrap());
-                        error_count += 1;
-                        continue;
-                    }
+                let path_str = request.path.to_str().unwrap().to_owned();
+                if request.path.exists() && !request.path.is_dir() {
+                    error!(logger, "{} exists but is not a directory, skipping", path_str);
+                    error_count += 1;
+                    continue;
+                }
+                if !request.path.exists() && request.clone_url.is_none() {
+                    error!(logger, "{} doesn't exist and no clone URL defined", path_str);
+                    error_count += 1;
+                    continue;
+                }
+
+                let remote = git::GitRemote::new(
+                    request.clone_url.as_ref().map(String::as_str).unwrap_or(""),
+                    request.clone_token.clone(),
+                    config.ssh_key_path.clone(),
+                    config.verbose.unwrap_or(false),
+                );
+                let database = if request.path.exists() {
+                    debug!(logger, "Fetching {:?}", request.path);
+                    remote.fetch(&logger, &request.path)
                 } else {
-                    match request.clone_url {
-                        Some(ref clone_url) => {
-                            git_args.append(&mut vec!["clone", clone_url, request.path.to_str().unwrap()]);
-                        },
-                        None => {
-                            error!(logger, "{} doesn't exist and no clone URL defined", request.path.to_str().unwrap());
-                            error_count += 1;
-                            continue;
-                        }
+                    debug!(logger, "Cloning {} -> {:?}", request.clone_url.as_ref().unwrap(), request.path);
+                    remote.checkout(&logger, &request.path)
+                };
+
+                let sync_result = database.and_then(|db| {
+                    match request.reference {
+                        Some(GitReference::Branch(ref name)) => db.fast_forward_to_branch(&logger, name),
+                        Some(GitReference::Tag(ref name))    => db.checkout_detached(&logger, &format!("refs/tags/{}", name)),
+                        Some(GitReference::Rev(ref rev))     => db.checkout_detached(&logger, rev),
+                        None                                 => db.fast_forward_to_head(&logger),
                     }
-                }
-                debug!(logger, "Running `git {:?}` for {:?}", git_args, request.path);
-                match process::Command::new("git")
-                            .args(git_args)
-                            .stdin(process::Stdio::null())
-                            .current_dir(closest_ancestor_dir(Some(request.path.as_path())).unwrap())
-                            .output() {
-                    Ok(output) => {
-                        if output.status.success() {
-                            if let Some(true) = config.verbose {
-                                match request.clone_url {
-                                    Some(clone_url) => info!(logger, "Ok {} -> {}", clone_url, request.path.to_str().unwrap()),
-                                    None            => info!(logger, "Ok {}", request.path.to_str().unwrap()),
-                                }
-                            }
-                        } else {
-                            error_count += 1;
-                            match output.status.code() {
-                                Some(code) => {
-                                    error!(logger, "git command for {} failed with status {}:\n----> stdout [{}]\n----> stderr [{}]",
-                                           request.path.to_str().unwrap(), code,
-                                           String::from_utf8_lossy(&output.stdout),
-                                           String::from_utf8_lossy(&output.stderr));
-                                },
-                                None => {
-                                    error!(logger, "git command for {} was killed externally by a signal", request.path.to_str().unwrap());
-                                }
+                });
+
+                match sync_result {
+                    Ok(()) => {
+                        if let Some(true) = config.verbose {
+                            match request.clone_url {
+                                Some(clone_url) => info!(logger, "Ok {} -> {}", clone_url, path_str),
+                                None            => info!(logger, "Ok {}", path_str),
                             }
-                            continue;
                         }
                     },
-                    Err(_) => {
-                        error!(logger, "Unable to get exit status of git command for {}", request.path.to_str().unwrap());
+                    Err(e) => {
+                        error!(logger, "git sync for {} failed: {}", path_str, e);
                         error_count += 1;
                         continue;
-                    },
+                    }
                 }
             },
             Err(_) => break,
@@ -236,33 +402,90 @@ fn main() {
         threads.push(thread::spawn(move || worker_thread(worker_logger, config, rx)));
     }
 
-    match config.github_access_token {
-        Some(ref github_token) => {
-            let github = Github::new(PROGRAM_NAME, Client::with_connector(HttpsConnector::new(NativeTlsClient::new().unwrap())), Credentials::Token(github_token.to_owned()));
-            let list_options = Default::default();
-            match config.orgs {
-                Some(ref orgs) => {
-                    for org in orgs {
-                        match github.org(org.org.clone()).repos().iter(&list_options) {
-                            Ok(org_repos) => {
-                                for org_repo in org_repos {
-                                    let clone_url = if ! org_repo.ssh_url.trim().is_empty() {
-                                        Some(org_repo.ssh_url)
-                                    } else {
-                                        None
-                                    };
-                                    tx.send(GitRepoSyncRequest { path: path::PathBuf::from(format!("{}{}{}", org.path.clone(), path::MAIN_SEPARATOR, org_repo.name)),
-                                                                 clone_url: clone_url })
-                                        .expect(format!("Unable to queue repo[{}] for org[{}]", org_repo.name, org.org).as_str());
+    let github_token = config.resolve_github_token(&logger);
+    let has_github_org = config.orgs.iter().flat_map(|orgs| orgs.iter()).any(|org| match org.forge {
+        Forge::Github => true,
+        _ => false,
+    });
+    // Built once (not per-org): it's the same token and TLS connector for every `forge: github`
+    // org, and `hubcaps::Github` is cheap to clone since it holds its client behind an `Rc`. Only
+    // built at all when some org actually needs it - orgs.is_empty() configs pay neither the
+    // connector setup nor its `NativeTlsClient::new().unwrap()` panic surface.
+    let github_client = if has_github_org {
+        Some(Github::new(PROGRAM_NAME, Client::with_connector(HttpsConnector::new(NativeTlsClient::new().unwrap())), Credentials::Token(github_token.clone().unwrap_or_default())))
+    } else {
+        None
+    };
+    let mut seen_repos: HashSet<String> = HashSet::new();
+
+    match config.orgs {
+        Some(ref orgs) => {
+            for org in orgs {
+                // The token used both to list an org's repos and to clone them over HTTPS: the
+                // shared github_token for Github orgs, each org's own
+                // forge_token otherwise (a Gitea/GitLab/Bitbucket instance's token is never the
+                // same credential as a github.com PAT or App installation token).
+                let (forge_client, clone_token): (Option<Box<ForgeClient>>, Option<String>) = match org.forge {
+                    Forge::Github => {
+                        let client = GithubForge::new(github_client.clone().expect("github_client is built whenever an org uses forge=github"));
+                        (Some(Box::new(client)), github_token.clone())
+                    },
+                    Forge::Gitea => {
+                        let forge_token = org.resolve_forge_token(&logger);
+                        match org.base_url.clone() {
+                            Some(base_url) => (Some(Box::new(GiteaForge::new(base_url, forge_token.clone()))), forge_token),
+                            None => {
+                                warn!(logger, "org `{}` uses forge=gitea but has no base_url configured, skipping", org.org);
+                                (None, None)
+                            }
+                        }
+                    },
+                    Forge::Gitlab => {
+                        let forge_token = org.resolve_forge_token(&logger);
+                        match org.base_url.clone() {
+                            Some(base_url) => (Some(Box::new(GitlabForge::new(base_url, forge_token.clone()))), forge_token),
+                            None => {
+                                warn!(logger, "org `{}` uses forge=gitlab but has no base_url configured, skipping", org.org);
+                                (None, None)
+                            }
+                        }
+                    },
+                    Forge::Bitbucket => {
+                        let forge_token = org.resolve_forge_token(&logger);
+                        (Some(Box::new(BitbucketForge::new(org.base_url.clone(), forge_token.clone()))), forge_token)
+                    },
+                };
+                let forge_client = match forge_client {
+                    Some(client) => client,
+                    None => continue,
+                };
+                match forge_client.list_repos(&org.org) {
+                    Ok(org_repos) => {
+                        for org_repo in org_repos {
+                            if !org.repo_allowed(&logger, &org_repo) {
+                                debug!(logger, "Filtered out {} from org[{}]", org_repo.name, org.org);
+                                continue;
+                            }
+                            let clone_url = clone_url::select_clone_url(org.clone_protocol, &org_repo);
+                            if let Some(ref clone_url) = clone_url {
+                                if let Some(identity) = clone_url::canonical_identity(clone_url) {
+                                    if !seen_repos.insert(identity) {
+                                        debug!(logger, "Already queued {}, skipping duplicate from org[{}]", clone_url, org.org);
+                                        continue;
+                                    }
                                 }
-                            },
-                            Err(e) => {
-                                warn!(logger, "Problem accessing org `{}` repository list, skipping: {}", org.org, e);
                             }
+                            tx.send(GitRepoSyncRequest { path: path::PathBuf::from(format!("{}{}{}", org.path.clone(), path::MAIN_SEPARATOR, org_repo.name)),
+                                                         clone_url: clone_url,
+                                                         clone_token: clone_token.clone(),
+                                                         reference: org.reference.clone() })
+                                .expect(format!("Unable to queue repo[{}] for org[{}]", org_repo.name, org.org).as_str());
                         }
+                    },
+                    Err(e) => {
+                        warn!(logger, "Problem accessing org `{}` repository list, skipping: {}", org.org, e);
                     }
-                },
-                _ => ()
+                }
             }
         },
         _ => ()
@@ -271,7 +494,7 @@ fn main() {
     match config.syncpoints {
         Some(ref syncpoints) => {
             for syncpoint in syncpoints {
-                tx.send(GitRepoSyncRequest { path: path::PathBuf::from(syncpoint), clone_url: None })
+                tx.send(GitRepoSyncRequest { path: path::PathBuf::from(syncpoint.path()), clone_url: None, clone_token: github_token.clone(), reference: syncpoint.reference() })
                     .expect("Unable to queue work");
             }
         },
@@ -292,3 +515,112 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trips_through_json() {
+        let config = Config {
+            github_access_token: Some(SecretSource::Env { env: "GH_TOKEN".to_owned() }),
+            github_app: None,
+            ssh_key_path: Some(path::PathBuf::from("/home/me/.ssh/id_ed25519")),
+            orgs: Some(vec![
+                ConfigOrg {
+                    org: "my_org".to_owned(),
+                    path: "/my_org/source/directory".to_owned(),
+                    forge: Forge::Gitlab,
+                    base_url: Some("https://gitlab.example.com".to_owned()),
+                    forge_token: Some(SecretSource::Command { command: vec!["pass".to_owned(), "show".to_owned(), "gitlab/token".to_owned()] }),
+                    reference: Some(GitReference::Branch("release".to_owned())),
+                    clone_protocol: CloneProtocol::Https,
+                    include: vec!["^svc-.*".to_owned()],
+                    exclude: vec!["^archive-.*".to_owned()],
+                    skip_archived: true,
+                    skip_forks: true,
+                },
+            ]),
+            syncpoints: Some(vec![
+                Syncpoint::Path("/some/previously/cloned/directory".to_owned()),
+                Syncpoint::WithReference { path: "/some/other/directory".to_owned(), reference: GitReference::Tag("v1.2.3".to_owned()) },
+            ]),
+            concurrency: Some(DEFAULT_CONCURRENCY),
+            verbose: Some(true),
+        };
+
+        let json = serde_json::to_string(&config).expect("Unable to serialize config");
+        let round_tripped: Config = serde_json::from_str(&json).expect("Unable to deserialize config");
+
+        let orgs = round_tripped.orgs.expect("orgs");
+        assert_eq!(orgs[0].org, "my_org");
+        match orgs[0].reference {
+            Some(GitReference::Branch(ref name)) => assert_eq!(name, "release"),
+            ref other => panic!("expected a branch reference, got {:?}", other),
+        }
+
+        let syncpoints = round_tripped.syncpoints.expect("syncpoints");
+        assert_eq!(syncpoints[0].path(), "/some/previously/cloned/directory");
+        match syncpoints[1].reference() {
+            Some(GitReference::Tag(ref name)) => assert_eq!(name, "v1.2.3"),
+            ref other => panic!("expected a tag reference, got {:?}", other),
+        }
+    }
+
+    fn test_logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn test_org(include: Vec<&str>, exclude: Vec<&str>, skip_archived: bool, skip_forks: bool) -> ConfigOrg {
+        ConfigOrg {
+            org: "my_org".to_owned(),
+            path: "/my_org/source/directory".to_owned(),
+            forge: Forge::Github,
+            base_url: None,
+            forge_token: None,
+            reference: None,
+            clone_protocol: CloneProtocol::Auto,
+            include: include.into_iter().map(str::to_owned).collect(),
+            exclude: exclude.into_iter().map(str::to_owned).collect(),
+            skip_archived: skip_archived,
+            skip_forks: skip_forks,
+        }
+    }
+
+    fn test_repo(name: &str, archived: bool, fork: bool) -> forge::RemoteRepo {
+        forge::RemoteRepo {
+            name:      name.to_owned(),
+            ssh_url:   format!("git@example.com:my_org/{}.git", name),
+            clone_url: format!("https://example.com/my_org/{}.git", name),
+            archived:  archived,
+            fork:      fork,
+        }
+    }
+
+    #[test]
+    fn repo_allowed_empty_include_means_everything_passes() {
+        let org = test_org(vec![], vec![], false, false);
+        assert!(org.repo_allowed(&test_logger(), &test_repo("anything", false, false)));
+    }
+
+    #[test]
+    fn repo_allowed_skips_archived_and_forks_when_configured() {
+        let org = test_org(vec![], vec![], true, true);
+        assert!(!org.repo_allowed(&test_logger(), &test_repo("archived-repo", true, false)));
+        assert!(!org.repo_allowed(&test_logger(), &test_repo("forked-repo", false, true)));
+        assert!(org.repo_allowed(&test_logger(), &test_repo("plain-repo", false, false)));
+    }
+
+    #[test]
+    fn repo_allowed_applies_include_then_exclude() {
+        let org = test_org(vec!["^svc-.*"], vec!["^svc-legacy$"], false, false);
+        assert!(org.repo_allowed(&test_logger(), &test_repo("svc-api", false, false)));
+        assert!(!org.repo_allowed(&test_logger(), &test_repo("svc-legacy", false, false)));
+        assert!(!org.repo_allowed(&test_logger(), &test_repo("other", false, false)));
+    }
+
+    #[test]
+    fn name_matches_invalid_regex_is_treated_as_no_match() {
+        assert!(!name_matches(&test_logger(), "(unterminated", "anything"));
+    }
+}
@@ -0,0 +1,103 @@
+//! Indirect secret sources for config values like `github_access_token`, so a token doesn't have
+//! to sit in plaintext in `config.json`.
+
+use std::error;
+use std::fmt;
+use std::process;
+
+/// How to obtain a secret value: a literal string (discouraged, but still supported for
+/// backwards compatibility), an environment variable, or the trimmed stdout of a command.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Literal(String),
+    Env { env: String },
+    Command { command: Vec<String> },
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<Secret, Box<error::Error>> {
+        let value = match *self {
+            SecretSource::Literal(ref value) => value.clone(),
+            SecretSource::Env { ref env } => {
+                ::std::env::var(env).map_err(|e| format!("Unable to read env var `{}`: {}", env, e))?
+            },
+            SecretSource::Command { ref command } => {
+                let (program, args) = command.split_first().ok_or("`command` secret source must have at least one element")?;
+                let output = process::Command::new(program).args(args).output()?;
+                if !output.status.success() {
+                    return Err(format!("Secret command `{:?}` exited with status {}", command, output.status).into());
+                }
+                String::from_utf8(output.stdout)?.trim().to_owned()
+            },
+        };
+        Ok(Secret(value))
+    }
+}
+
+/// A resolved secret value. Deliberately has no `Display` and a redacting `Debug` so a stray
+/// `debug!(logger, "{:?}", secret)` doesn't leak it into logs.
+pub struct Secret(String);
+
+impl Secret {
+    pub fn reveal(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_resolves_to_itself() {
+        let secret = SecretSource::Literal("s3kr3t".to_owned()).resolve().expect("literal always resolves");
+        assert_eq!(secret.reveal(), "s3kr3t");
+    }
+
+    #[test]
+    fn env_resolves_from_the_named_variable() {
+        let var = "GHOPAC_TEST_SECRET_ENV_VAR";
+        ::std::env::set_var(var, "from-env");
+        let secret = SecretSource::Env { env: var.to_owned() }.resolve().expect("env var is set");
+        assert_eq!(secret.reveal(), "from-env");
+        ::std::env::remove_var(var);
+    }
+
+    #[test]
+    fn env_errors_when_the_variable_is_unset() {
+        let var = "GHOPAC_TEST_SECRET_ENV_VAR_UNSET";
+        ::std::env::remove_var(var);
+        assert!(SecretSource::Env { env: var.to_owned() }.resolve().is_err());
+    }
+
+    #[test]
+    fn command_resolves_to_trimmed_stdout() {
+        let secret = SecretSource::Command { command: vec!["echo".to_owned(), "  from-command  ".to_owned()] }.resolve().expect("echo should succeed");
+        assert_eq!(secret.reveal(), "from-command");
+    }
+
+    #[test]
+    fn command_errors_on_nonzero_exit() {
+        let secret = SecretSource::Command { command: vec!["sh".to_owned(), "-c".to_owned(), "exit 1".to_owned()] }.resolve();
+        assert!(secret.is_err());
+    }
+
+    #[test]
+    fn command_errors_when_empty() {
+        let secret = SecretSource::Command { command: vec![] }.resolve();
+        assert!(secret.is_err());
+    }
+
+    #[test]
+    fn debug_never_reveals_the_value() {
+        let secret = SecretSource::Literal("s3kr3t".to_owned()).resolve().expect("literal always resolves");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+    }
+}